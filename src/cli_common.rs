@@ -1,20 +1,28 @@
 //! Common helpers for CLI binaries.
 
 use dialoguer::PasswordInput;
+use hkdf::Hkdf;
 use rand::{
     distributions::{Distribution, Uniform},
     rngs::OsRng,
+    RngCore,
 };
 use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::env;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
 use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Once;
 
 use crate::keys::Identity;
 
 pub mod file_io;
 
 const BIP39_WORDLIST: &str = include_str!("../assets/bip39-english.txt");
+const BUILTIN_WORDLIST: &str = include_str!("../assets/builtin-wordlist.txt");
 
 /// Returns the age config directory.
 ///
@@ -35,8 +43,57 @@ pub fn get_config_dir() -> Option<PathBuf> {
     }
 }
 
-/// Reads identities from the provided files if given, or the default system
-/// locations if no files are given.
+/// Configuration read from `age/config.toml` in [`get_config_dir`].
+///
+/// Every field is optional; a missing file yields [`Config::default`], so the
+/// config never has to exist.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Default identity files, consulted before falling back to `keys.txt`.
+    pub identity_files: Vec<String>,
+    /// A command used to source the passphrase when none is given on the CLI.
+    pub passphrase_command: Option<String>,
+    /// Default passphrase-generation settings.
+    pub passphrase: GenerationConfig,
+}
+
+/// The `[passphrase]` section of [`Config`], overriding generation defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GenerationConfig {
+    /// Number of words in a generated passphrase.
+    pub words: Option<usize>,
+    /// Separator placed between words.
+    pub separator: Option<String>,
+    /// Name of the wordlist to draw from.
+    pub wordlist: Option<String>,
+}
+
+impl Config {
+    /// Loads `age/config.toml` from [`get_config_dir`], returning the defaults
+    /// when the file is absent.
+    pub fn load() -> io::Result<Config> {
+        let path = match get_config_dir() {
+            Some(mut dir) => {
+                dir.push("age/config.toml");
+                dir
+            }
+            None => return Ok(Config::default()),
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Reads identities from the provided files if given, or the locations
+/// configured in `config.toml` / the default system location otherwise.
 pub fn read_identities<E, F>(filenames: Vec<String>, no_default: F) -> Result<Vec<Identity>, E>
 where
     E: From<io::Error>,
@@ -45,6 +102,17 @@ where
     let mut identities = vec![];
 
     if filenames.is_empty() {
+        let config = Config::load()?;
+        // Passphrase-protected identity files are decrypted inside `Identity`,
+        // which reads the passphrase via `read_secret`; that call honours
+        // `passphrase_command`, so decryption works non-interactively here.
+        if !config.identity_files.is_empty() {
+            for filename in config.identity_files {
+                identities.extend(Identity::from_file(filename)?);
+            }
+            return Ok(identities);
+        }
+
         let default_filename = get_config_dir()
             .map(|mut path| {
                 path.push("age/keys.txt");
@@ -67,7 +135,30 @@ where
 }
 
 /// Reads a secret from stdin. If `confirm.is_some()` then an empty secret is allowed.
+///
+/// When `confirm` is `None` (e.g. reading the passphrase for an encrypted
+/// identity file) and `passphrase_command` is configured, the secret is sourced
+/// from that command rather than prompted, so identities can be decrypted
+/// non-interactively.
+///
+/// Otherwise, when a pinentry program is available (resolved from
+/// `PINENTRY_PROGRAM` or the `PATH`) and stdin is a TTY, the prompt is routed
+/// through it using the Assuan protocol, falling back to
+/// [`dialoguer::PasswordInput`]. Through pinentry, an empty entry or a
+/// cancellation is returned as an empty secret whether or not `confirm` is given.
 pub fn read_secret(prompt: &str, confirm: Option<&str>) -> io::Result<SecretString> {
+    if confirm.is_none() {
+        if let Some(command) = Config::load()?.passphrase_command {
+            return read_secret_from_command(&command);
+        }
+    }
+
+    if io::stdin().is_terminal() {
+        if let Some(program) = pinentry_program() {
+            return read_secret_pinentry(&program, prompt, confirm);
+        }
+    }
+
     let mut input = PasswordInput::new();
     input.with_prompt(prompt);
     if let Some(confirm_prompt) = confirm {
@@ -78,41 +169,456 @@ pub fn read_secret(prompt: &str, confirm: Option<&str>) -> io::Result<SecretStri
     input.interact().map(SecretString::new)
 }
 
+/// Resolves the pinentry program to use, preferring `PINENTRY_PROGRAM` and
+/// falling back to the usual binaries on the `PATH`.
+fn pinentry_program() -> Option<String> {
+    if let Some(program) = env::var_os("PINENTRY_PROGRAM") {
+        if !program.is_empty() {
+            return Some(program.to_string_lossy().into_owned());
+        }
+    }
+
+    ["pinentry", "pinentry-gtk-2", "pinentry-gnome3", "pinentry-qt", "pinentry-curses", "pinentry-tty"]
+        .iter()
+        .find(|program| on_path(program))
+        .map(|program| (*program).to_owned())
+}
+
+/// Returns `true` if a file named `program` exists in a `PATH` directory.
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .map(|paths| {
+            env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+        })
+        .unwrap_or(false)
+}
+
+/// Reads a secret through a pinentry program using the Assuan protocol.
+fn read_secret_pinentry(
+    program: &str,
+    prompt: &str,
+    confirm: Option<&str>,
+) -> io::Result<SecretString> {
+    let mut conn = Assuan::open(program)?;
+
+    // Terminal pinentries need the controlling tty and locale communicated over
+    // Assuan, or they fail / grab the wrong terminal.
+    if let Some(tty) = controlling_tty() {
+        conn.send(&format!("OPTION ttyname={}", assuan_escape(&tty)))?;
+    }
+    if let Some(lc_ctype) = env::var_os("LC_CTYPE").or_else(|| env::var_os("LANG")) {
+        conn.send(&format!(
+            "OPTION lc-ctype={}",
+            assuan_escape(&lc_ctype.to_string_lossy())
+        ))?;
+    }
+
+    conn.send(&format!("SETDESC {}", assuan_escape(prompt)))?;
+    conn.send("SETPROMPT Passphrase:")?;
+    let secret = conn.getpin()?;
+
+    if let Some(confirm_prompt) = confirm {
+        // Re-prompt for the confirmation until it matches the first entry.
+        loop {
+            conn.send(&format!("SETDESC {}", assuan_escape(confirm_prompt)))?;
+            conn.send("SETPROMPT Confirm:")?;
+            let confirmation = conn.getpin()?;
+            if confirmation.expose_secret() == secret.expose_secret() {
+                break;
+            }
+            conn.send("SETERROR Inputs do not match")?;
+        }
+    }
+
+    Ok(secret)
+}
+
+/// Resolves the path of the controlling terminal on stdin, if any.
+fn controlling_tty() -> Option<String> {
+    std::fs::read_link("/proc/self/fd/0")
+        .ok()
+        .map(|p| p.to_string_lossy().into_owned())
+        .filter(|p| p.starts_with("/dev/"))
+}
+
+/// A single Assuan conversation with a spawned pinentry program.
+struct Assuan {
+    child: Child,
+    reader: BufReader<ChildStdout>,
+    writer: ChildStdin,
+}
+
+impl Assuan {
+    fn open(program: &str) -> io::Result<Self> {
+        let mut child = Command::new(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let reader = BufReader::new(child.stdout.take().expect("stdout was piped"));
+        let writer = child.stdin.take().expect("stdin was piped");
+        let mut conn = Assuan {
+            child,
+            reader,
+            writer,
+        };
+        // Consume the initial greeting.
+        conn.read_response(|_| {})?;
+        Ok(conn)
+    }
+
+    /// Sends a command and waits for the server to acknowledge it with `OK`.
+    fn send(&mut self, command: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", command)?;
+        self.read_response(|_| {})
+    }
+
+    /// Issues `GETPIN` and collects the entered value.
+    ///
+    /// An empty entry (no `D` line) and a user cancellation are both reported as
+    /// an empty secret, so that the "leave empty to autogenerate" and
+    /// `insecure_no_passphrase` flows remain reachable through pinentry.
+    fn getpin(&mut self) -> io::Result<SecretString> {
+        writeln!(self.writer, "GETPIN")?;
+        // The value may be split across several `D` lines; accumulate them all.
+        let mut value = String::new();
+        match self.read_response(|data| value.push_str(&assuan_unescape(data))) {
+            Ok(()) => Ok(SecretString::new(value)),
+            Err(e) if is_cancelled(&e) => Ok(SecretString::new(String::new())),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads response lines until a terminating `OK` or `ERR`, passing the
+    /// payload of any `D` (data) line to `on_data`.
+    fn read_response<F: FnMut(&str)>(&mut self, mut on_data: F) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "pinentry closed the connection",
+                ));
+            }
+            let line = line.trim_end();
+            if let Some(data) = line.strip_prefix("D ") {
+                on_data(data);
+            } else if line == "OK" || line.starts_with("OK ") {
+                return Ok(());
+            } else if line.starts_with("ERR") {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    line.strip_prefix("ERR ").unwrap_or(line).to_owned(),
+                ));
+            }
+            // Ignore status (`S`) and comment (`#`) lines.
+        }
+    }
+}
+
+impl Drop for Assuan {
+    fn drop(&mut self) {
+        let _ = writeln!(self.writer, "BYE");
+        let _ = self.child.wait();
+    }
+}
+
+/// Returns `true` if `error` carries an Assuan cancellation from pinentry.
+fn is_cancelled(error: &io::Error) -> bool {
+    let message = error.to_string().to_ascii_lowercase();
+    // GPG_ERR_CANCELED / GPG_ERR_FULLY_CANCELED, plus the textual form.
+    message.contains("cancel") || message.contains("83886179") || message.contains("83886278")
+}
+
+/// Percent-escapes a value for transmission over the Assuan protocol.
+fn assuan_escape(value: &str) -> String {
+    use std::fmt::Write;
+    let mut escaped = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'%' => escaped.push_str("%25"),
+            b'\n' => escaped.push_str("%0A"),
+            b'\r' => escaped.push_str("%0D"),
+            // Percent-encode control and non-ASCII bytes so that multi-byte
+            // UTF-8 sequences survive transmission intact.
+            b if b < 0x20 || b >= 0x7f => {
+                let _ = write!(escaped, "%{:02X}", b);
+            }
+            _ => escaped.push(b as char),
+        }
+    }
+    escaped
+}
+
+/// Decodes percent-escaping applied by an Assuan server to a `D` line.
+fn assuan_unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&value[i + 1..i + 3], 16) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Reads a secret by running `command` through the platform shell and capturing
+/// its standard output.
+///
+/// A single trailing newline is stripped from the output, so that helpers such
+/// as `pass show ...` or a keyring query can be used unchanged. The command is
+/// treated as failing if it exits non-zero, in which case its standard error is
+/// surfaced in the returned error.
+pub fn read_secret_from_command(command: &str) -> io::Result<SecretString> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).output()?
+    } else {
+        Command::new("sh").arg("-c").arg(command).output()?
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("passphrase command failed: {}", stderr.trim_end()),
+        ));
+    }
+
+    let mut value = String::from_utf8_lossy(&output.stdout).into_owned();
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+
+    Ok(SecretString::new(value))
+}
+
 /// A passphrase.
 pub enum Passphrase {
     /// Typed by the user.
     Typed(SecretString),
-    /// Generated.
-    Generated(SecretString),
+    /// Generated, carrying the estimated entropy (in bits) of the generator.
+    Generated(SecretString, f64),
+    /// No passphrase, deliberately chosen for automation (insecure).
+    None,
+}
+
+/// A selectable wordlist for passphrase generation.
+pub enum Wordlist {
+    /// The bundled BIP39 English wordlist (2048 words).
+    Bip39,
+    /// A bundled list of 7776 pronounceable four-letter words, selectable as an
+    /// alternative to BIP39 with more entropy per word (about 12.9 bits).
+    Builtin,
+}
+
+impl Wordlist {
+    /// Resolves a wordlist by its config name, returning `None` if unknown.
+    pub fn from_name(name: &str) -> Option<Wordlist> {
+        match name {
+            "bip39" => Some(Wordlist::Bip39),
+            "builtin" => Some(Wordlist::Builtin),
+            _ => None,
+        }
+    }
+
+    fn words(&self) -> &'static str {
+        match self {
+            Wordlist::Bip39 => BIP39_WORDLIST,
+            Wordlist::Builtin => BUILTIN_WORDLIST,
+        }
+    }
+}
+
+/// Controls how [`generate_passphrase`] builds a passphrase.
+pub struct PassphrasePolicy {
+    /// Number of words to draw.
+    pub words: usize,
+    /// Separator placed between words.
+    pub separator: String,
+    /// Wordlist to draw from.
+    pub wordlist: Wordlist,
+}
+
+impl Default for PassphrasePolicy {
+    /// The backward-compatible default: 10 BIP39 words joined by `-`.
+    fn default() -> Self {
+        PassphrasePolicy {
+            words: 10,
+            separator: "-".to_owned(),
+            wordlist: Wordlist::Bip39,
+        }
+    }
+}
+
+impl GenerationConfig {
+    /// Applies these overrides on top of the default policy.
+    ///
+    /// Returns an error if `wordlist` names a list that doesn't exist, rather
+    /// than silently falling back to the default.
+    fn policy(&self) -> io::Result<PassphrasePolicy> {
+        let mut policy = PassphrasePolicy::default();
+        if let Some(words) = self.words {
+            policy.words = words;
+        }
+        if let Some(separator) = &self.separator {
+            policy.separator = separator.clone();
+        }
+        if let Some(name) = &self.wordlist {
+            policy.wordlist = Wordlist::from_name(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown wordlist {:?} in config", name),
+                )
+            })?;
+        }
+        Ok(policy)
+    }
+}
+
+/// Generates a passphrase from `policy` using [`OsRng`], returning it together
+/// with an estimated entropy in bits (`words × log2(wordlist_len)`).
+pub fn generate_passphrase(policy: &PassphrasePolicy) -> (SecretString, f64) {
+    let words: Vec<&str> = policy.wordlist.words().lines().collect();
+    let between = Uniform::from(0..words.len());
+    let mut rng = OsRng;
+    let passphrase = (0..policy.words)
+        .map(|_| words[between.sample(&mut rng)])
+        .collect::<Vec<_>>()
+        .join(&policy.separator);
+    let entropy = policy.words as f64 * (words.len() as f64).log2();
+    (SecretString::new(passphrase), entropy)
 }
 
 /// Reads a passphrase from stdin, or generates a secure one if none is provided.
-pub fn read_or_generate_passphrase() -> io::Result<Passphrase> {
-    let res = read_secret(
-        "Type passphrase (leave empty to autogenerate a secure one)",
-        Some("Confirm passphrase"),
-    )?;
+///
+/// If `command` is given, the passphrase is sourced from it via
+/// [`read_secret_from_command`] instead of prompting interactively, so that a
+/// password manager or credential helper can feed it in non-interactive
+/// environments.
+///
+/// If `insecure_no_passphrase` is set, an empty input yields [`Passphrase::None`]
+/// (with a one-time warning on stderr) rather than autogenerating a secure one.
+pub fn read_or_generate_passphrase(
+    command: Option<&str>,
+    insecure_no_passphrase: bool,
+) -> io::Result<Passphrase> {
+    let config = Config::load()?;
+    let command = command
+        .map(str::to_owned)
+        .or(config.passphrase_command);
+    let res = match command {
+        Some(command) => return Ok(Passphrase::Typed(read_secret_from_command(&command)?)),
+        None => read_secret(
+            "Type passphrase (leave empty to autogenerate a secure one)",
+            Some("Confirm passphrase"),
+        )?,
+    };
+
+    if res.expose_secret().is_empty() && insecure_no_passphrase {
+        warn_no_passphrase();
+        return Ok(Passphrase::None);
+    }
 
     if res.expose_secret().is_empty() {
-        // Generate a secure passphrase
-        let between = Uniform::from(0..2048);
-        let mut rng = OsRng;
-        let new_passphrase = (0..10)
-            .map(|_| {
-                BIP39_WORDLIST
-                    .lines()
-                    .nth(between.sample(&mut rng))
-                    .expect("index is in range")
-            })
-            .fold(String::new(), |acc, s| {
-                if acc.is_empty() {
-                    acc + s
-                } else {
-                    acc + "-" + s
-                }
-            });
-        Ok(Passphrase::Generated(SecretString::new(new_passphrase)))
+        // Generate a secure passphrase according to the configured policy.
+        let (passphrase, entropy) = generate_passphrase(&config.passphrase.policy()?);
+        Ok(Passphrase::Generated(passphrase, entropy))
     } else {
         Ok(Passphrase::Typed(res))
     }
 }
+
+/// Returns the raw bytes of a passphrase for key derivation.
+fn passphrase_bytes(passphrase: &Passphrase) -> &[u8] {
+    match passphrase {
+        Passphrase::Typed(secret) | Passphrase::Generated(secret, _) => {
+            secret.expose_secret().as_bytes()
+        }
+        Passphrase::None => &[],
+    }
+}
+
+/// Returns the path of the KDF salt stored next to the config file.
+fn salt_path() -> io::Result<PathBuf> {
+    let mut dir = get_config_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+    dir.push("age/salt");
+    Ok(dir)
+}
+
+/// Loads the KDF salt stored next to the config file, creating a fresh random
+/// one on first use.
+///
+/// Only the salt is ever written to disk; the passphrase is never persisted.
+pub fn load_or_create_salt() -> io::Result<Vec<u8>> {
+    let path = salt_path()?;
+    match std::fs::read(&path) {
+        Ok(salt) => Ok(salt),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut salt = vec![0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, &salt)?;
+            Ok(salt)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Derives one independent subkey per label from `passphrase` and `salt`.
+///
+/// The passphrase is first stretched with the scrypt memory-hard KDF, and each
+/// `label` is then mixed into an HKDF context so that distinct purposes (e.g. an
+/// encryption key and an authentication key) yield independent outputs. The
+/// result is deterministic for a fixed passphrase and salt.
+pub fn derive_keys(
+    passphrase: &Passphrase,
+    salt: &[u8],
+    labels: &[&str],
+) -> io::Result<Vec<SecretString>> {
+    let params = scrypt::Params::new(15, 8, 1)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut master = [0u8; 32];
+    scrypt::scrypt(passphrase_bytes(passphrase), salt, &params, &mut master)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), &master);
+    let mut keys = Vec::with_capacity(labels.len());
+    for label in labels {
+        let mut okm = [0u8; 32];
+        hkdf.expand(label.as_bytes(), &mut okm)
+            .expect("32 bytes is a valid HKDF output length");
+        keys.push(SecretString::new(to_hex(&okm)));
+    }
+    Ok(keys)
+}
+
+/// Encodes bytes as a lowercase hexadecimal string.
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+/// Prints a one-time warning that no passphrase is being used.
+fn warn_no_passphrase() {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        eprintln!("Warning: proceeding with no passphrase; the output is not protected by one.");
+    });
+}